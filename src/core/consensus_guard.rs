@@ -0,0 +1,172 @@
+// Copyright 2019 Cryptape Technologies LLC.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Guards applied to consensus messages as they cross the network boundary:
+//! forward clock-drift buffering, NTP offset correction and wire-format
+//! version gating. Keeping the decisions here, rather than inline in `main`,
+//! lets them be unit tested apart from the ingress dispatcher that applies
+//! them.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Tunable knobs for the consensus guards. The type deserializes with every
+/// field defaulted, so a partial table fills in the rest. The node's `Config`
+/// does not carry it: `main` starts from `GuardConfig::default()` and
+/// overrides only `max_forward_time_drift` and `protocol_version` from the
+/// command line.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GuardConfig {
+    /// How far ahead of the local (corrected) clock a proposal's timestamp may
+    /// sit before it is buffered rather than processed at once, in ms.
+    pub max_forward_time_drift: u64,
+    /// Upper bound on how long a proposal may be parked waiting for local time
+    /// to catch up, in ms. A timestamp further in the future than this is
+    /// dropped outright instead of held, so an absurd future stamp cannot pin
+    /// buffer state for years.
+    pub max_buffer_horizon: u64,
+    /// Wire-format version this node stamps onto the messages it publishes.
+    pub protocol_version: u32,
+    /// Oldest wire-format version this node still decodes on ingress.
+    pub min_protocol_version: u32,
+    /// Newest wire-format version this node still decodes on ingress.
+    pub max_protocol_version: u32,
+}
+
+impl Default for GuardConfig {
+    fn default() -> Self {
+        GuardConfig {
+            max_forward_time_drift: 3_000,
+            max_buffer_horizon: 60_000,
+            protocol_version: 1,
+            min_protocol_version: 0,
+            max_protocol_version: 2,
+        }
+    }
+}
+
+/// What the ingress path should do with a freshly received proposal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ingress {
+    /// Deliver to the engine now.
+    Deliver,
+    /// Hold for `delay` ms, then re-deliver: the proposer's clock is slightly
+    /// ahead of ours and dropping it would wrongly penalize a correct leader.
+    Buffer { delay: u64 },
+    /// Drop: the timestamp is older than the drift tolerance (stale).
+    DropStale,
+    /// Drop: so far in the future that buffering it is not worthwhile.
+    DropBeyondHorizon,
+}
+
+impl GuardConfig {
+    /// Current unix-epoch millisecond time shifted onto the NTP-corrected
+    /// timeline by `offset_ms` (the last offset measured by the NTP thread).
+    pub fn corrected_now_ms(&self, offset_ms: i64) -> u64 {
+        let local = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0);
+        Self::apply_offset(local, offset_ms)
+    }
+
+    /// Pure offset application, split out so it can be tested without reading
+    /// the wall clock. Clamps a negative corrected time to zero.
+    fn apply_offset(local_ms: i64, offset_ms: i64) -> u64 {
+        let corrected = local_ms.saturating_add(offset_ms);
+        if corrected < 0 {
+            0
+        } else {
+            corrected as u64
+        }
+    }
+
+    /// Classify a proposal whose block timestamp is `proposal_ts` ms against
+    /// the corrected local time `now`.
+    pub fn classify(&self, now: u64, proposal_ts: u64) -> Ingress {
+        if proposal_ts > now + self.max_forward_time_drift {
+            let delay = proposal_ts - now;
+            if delay > self.max_buffer_horizon {
+                Ingress::DropBeyondHorizon
+            } else {
+                Ingress::Buffer { delay }
+            }
+        } else if proposal_ts + self.max_forward_time_drift < now {
+            Ingress::DropStale
+        } else {
+            Ingress::Deliver
+        }
+    }
+
+    /// Whether `version` is within the range this node can decode on ingress.
+    pub fn version_supported(&self, version: u32) -> bool {
+        version >= self.min_protocol_version && version <= self.max_protocol_version
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg() -> GuardConfig {
+        GuardConfig {
+            max_forward_time_drift: 1_000,
+            max_buffer_horizon: 10_000,
+            protocol_version: 1,
+            min_protocol_version: 0,
+            max_protocol_version: 2,
+        }
+    }
+
+    #[test]
+    fn within_drift_is_delivered() {
+        let c = cfg();
+        assert_eq!(c.classify(10_000, 10_500), Ingress::Deliver);
+        assert_eq!(c.classify(10_000, 9_500), Ingress::Deliver);
+    }
+
+    #[test]
+    fn slightly_ahead_is_buffered_with_delay() {
+        let c = cfg();
+        assert_eq!(c.classify(10_000, 13_000), Ingress::Buffer { delay: 3_000 });
+    }
+
+    #[test]
+    fn far_future_beyond_horizon_is_dropped() {
+        let c = cfg();
+        assert_eq!(c.classify(10_000, 21_000), Ingress::DropBeyondHorizon);
+    }
+
+    #[test]
+    fn far_past_is_stale() {
+        let c = cfg();
+        assert_eq!(c.classify(10_000, 8_000), Ingress::DropStale);
+    }
+
+    #[test]
+    fn offset_shifts_timeline_and_clamps_at_zero() {
+        assert_eq!(GuardConfig::apply_offset(1_000, 250), 1_250);
+        assert_eq!(GuardConfig::apply_offset(1_000, -250), 750);
+        assert_eq!(GuardConfig::apply_offset(100, -1_000), 0);
+    }
+
+    #[test]
+    fn version_range_gates_ingress() {
+        let c = cfg();
+        assert!(c.version_supported(0));
+        assert!(c.version_supported(1));
+        assert!(c.version_supported(2));
+        assert!(!c.version_supported(3));
+    }
+}
@@ -39,51 +39,153 @@
 //!
 //! More details at [SNTP](https://tools.ietf.org/html/rfc4330).
 
-use ntp::errors::Error;
 use ntp::request;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
 use time::now_utc;
 use time::{Duration, Timespec};
 
+/// Number of samples gathered across the configured servers per poll.
+const SAMPLE_COUNT: usize = 4;
+/// Weight applied to each freshly accepted offset by the exponential moving
+/// average; the remainder is carried over from the previous estimate.
+const EMA_ALPHA: f64 = 0.5;
+
+/// A single (delay, offset) pair derived from one server reply.
+struct Sample {
+    delay: Duration,
+    offset: Duration,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Ntp {
     pub enabled: bool,
     pub threshold: i64,
-    pub address: String,
+    /// The ntp servers to poll. Accepts either a single `address = "host:123"`
+    /// scalar (the historical form) or a list `address = ["a:123", "b:123"]`,
+    /// so existing config files keep deserializing after the move to multiple
+    /// servers.
+    #[serde(deserialize_with = "string_or_seq_string")]
+    pub address: Vec<String>,
+    /// Last filtered offset in milliseconds, smoothed across polls. Kept out
+    /// of the config file and shared by clones so the moving average survives.
+    #[serde(skip)]
+    ema: Arc<Mutex<Option<f64>>>,
 }
 
 impl Ntp {
-    /// Check the system clock offset overflow the threshold
-    pub fn is_clock_offset_overflow(&self) -> bool {
-        match Ntp::system_clock_offset(self) {
-            Ok(offset) => {
-                if offset.num_milliseconds().abs() > self.threshold {
-                    debug!("System clock seems off by {}", offset);
-                    true
-                } else {
-                    false
+    /// Measure the current clock offset relative to the ntp servers.
+    ///
+    /// A positive offset means the local clock is behind the server; callers
+    /// add it to the local time to obtain the NTP-corrected timeline.
+    pub fn offset(&self) -> Result<Duration, String> {
+        self.system_clock_offset()
+    }
+
+    /// Caclulate the system clock offset relative to the ntp servers.
+    ///
+    /// Gathers several samples, keeps only the one with the smallest
+    /// round-trip delay (least affected by queuing or path asymmetry), and
+    /// smooths successive estimates with an exponential moving average.
+    fn system_clock_offset(&self) -> Result<Duration, String> {
+        let best = self
+            .collect_samples()
+            .into_iter()
+            .min_by_key(|s| s.delay.num_nanoseconds().unwrap_or(i64::max_value()));
+
+        match best {
+            Some(sample) => Ok(self.smooth(sample.offset)),
+            None => Err("no usable ntp sample".to_owned()),
+        }
+    }
+
+    /// Poll the configured servers round-robin, discarding replies that carry
+    /// a kiss-o'-death marker or yield a non-positive round-trip delay.
+    fn collect_samples(&self) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        if self.address.is_empty() {
+            return samples;
+        }
+
+        for i in 0..SAMPLE_COUNT {
+            let server = &self.address[i % self.address.len()];
+            match request(server.clone()) {
+                Ok(packet) => {
+                    // Stratum 0 is an unsynchronised / kiss-o'-death reply.
+                    if packet.stratum == 0 {
+                        debug!("Ignore kiss-o'-death reply from {}", server);
+                        continue;
+                    }
+
+                    let t1 = Timespec::from(packet.orig_time);
+                    let t2 = Timespec::from(packet.recv_time);
+                    let t3 = Timespec::from(packet.transmit_time);
+                    let t4 = now_utc().to_timespec();
+
+                    let delay = (t4 - t1) - (t3 - t2);
+                    let offset = ((t2 - t1) + (t3 - t4)) / 2;
+
+                    if delay <= Duration::zero() {
+                        debug!("Ignore sample from {} with non-positive delay {}", server, delay);
+                        continue;
+                    }
+
+                    samples.push(Sample { delay, offset });
                 }
+                Err(err) => debug!("Fetch time from {} err: {}", server, err),
             }
-            Err(_) => true,
         }
+
+        samples
     }
 
-    /// Caclulate the system clock offset relative to the ntp server
-    fn system_clock_offset(&self) -> Result<Duration, Error> {
-        match request(self.address.clone()) {
-            Ok(packet) => {
-                let dest = now_utc().to_timespec();
-                let orig = Timespec::from(packet.orig_time);
-                let recv = Timespec::from(packet.recv_time);
-                let transmit = Timespec::from(packet.transmit_time);
+    /// Fold a freshly accepted offset into the moving average and return the
+    /// filtered estimate.
+    fn smooth(&self, sample: Duration) -> Duration {
+        let mut ema = self.ema.lock().unwrap();
+        let ms = sample.num_milliseconds() as f64;
+        let filtered = match *ema {
+            Some(prev) => EMA_ALPHA * ms + (1.0 - EMA_ALPHA) * prev,
+            None => ms,
+        };
+        *ema = Some(filtered);
+        Duration::milliseconds(filtered.round() as i64)
+    }
+}
 
-                let offset = ((recv - orig) + (transmit - dest)) / 2;
+/// Deserialize `address` from either a single string or a sequence of strings,
+/// always yielding a `Vec<String>`. This keeps back-compat with config files
+/// that still set `address = "pool.ntp.org:123"`.
+fn string_or_seq_string<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct StringOrVec(PhantomData<Vec<String>>);
 
-                Ok(offset)
-            }
-            Err(err) => {
-                debug!("Fetch time err: {}", err);
-                Err(err)
-            }
+    impl<'de> Visitor<'de> for StringOrVec {
+        type Value = Vec<String>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a single ntp server or a list of servers")
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(vec![value.to_owned()])
+        }
+
+        fn visit_seq<S>(self, seq: S) -> Result<Self::Value, S::Error>
+        where
+            S: SeqAccess<'de>,
+        {
+            Deserialize::deserialize(de::value::SeqAccessDeserializer::new(seq))
         }
     }
+
+    deserializer.deserialize_any(StringOrVec(PhantomData))
 }
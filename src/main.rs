@@ -55,35 +55,129 @@ extern crate util;
 
 use clap::App;
 use pubsub::channel;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::io::{BufRead, BufReader};
+use std::os::unix::net::UnixListener;
 use std::thread;
 
 mod core;
 use crate::core::cita_bft::{Bft, BftTurn};
+use crate::core::consensus_guard::{GuardConfig, Ingress};
 use crate::core::params::{BftParams, Config, PrivateKey};
 use crate::core::votetime::WaitTimer;
 use cpuprofiler::PROFILER;
 use libproto::router::{MsgType, RoutingKey, SubModules};
+use libproto::{Message, TryFrom};
 use pubsub::start_pubsub;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use util::set_panic_handler;
 
-fn profiler(flag_prof_start: u64, flag_prof_duration: u64) {
-    //start profiling
-    if flag_prof_duration != 0 {
-        let start = flag_prof_start;
-        let duration = flag_prof_duration;
-        thread::spawn(move || {
-            thread::sleep(std::time::Duration::new(start, 0));
-            PROFILER
-                .lock()
-                .unwrap()
-                .start("./tdmint.profiler")
-                .expect("Couldn't start");
-            thread::sleep(std::time::Duration::new(duration, 0));
-            PROFILER.lock().unwrap().stop().unwrap();
-        });
-    }
+/// Upper bound on the number of far-future proposals held for re-delivery at
+/// once. A full buffer drops further far-future proposals so an adversary
+/// cannot grow buffer state without bound.
+const MAX_BUFFERED_PROPOSALS: usize = 256;
+
+/// Current unix-epoch millisecond time on the local clock, used by the
+/// re-delivery timer to schedule wake-ups. The drift decision itself is taken
+/// on the NTP-corrected timeline via `GuardConfig::corrected_now_ms`.
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Decode the `(height, round, block timestamp)` of a compact signed proposal
+/// straight off the wire so the dispatcher can judge its freshness before it
+/// reaches the engine. Returns `None` for anything that is not a decodable
+/// proposal.
+fn decode_proposal_meta(body: &[u8]) -> Option<(u64, u64, u64)> {
+    let mut msg = Message::try_from(body).ok()?;
+    let signed_proposal = msg.take_compact_signed_proposal()?;
+    let proposal = signed_proposal.get_proposal();
+    let height = proposal.get_height();
+    let round = proposal.get_round();
+    let timestamp = proposal.get_block().get_header().get_timestamp();
+    Some((height, round, timestamp))
+}
+
+/// Spawn an on-demand CPU profiling controller.
+///
+/// Instead of a fixed window computed at launch, a listener bound to the unix
+/// socket at `sock_path` accepts two line commands so operators can capture a
+/// slowdown exactly when it happens:
+///
+/// * `start [PATH]` - begin a profiling session, writing to `PATH`
+///   (default `./cita-bft.profiler`);
+/// * `stop` - finish the current session and flush the profile to disk.
+///
+/// Double-start and double-stop are rejected with a warning, and the resulting
+/// file is logged when a session begins.
+fn profiler(sock_path: String) {
+    thread::spawn(move || {
+        // A socket left behind by a previous run would block the bind.
+        let _ = std::fs::remove_file(&sock_path);
+        let listener = match UnixListener::bind(&sock_path) {
+            Ok(listener) => listener,
+            Err(err) => {
+                warn!("profiler control failed to bind {}: {}", sock_path, err);
+                return;
+            }
+        };
+        info!("profiler control listening on {}", sock_path);
+
+        let mut running = false;
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("profiler control accept error: {}", err);
+                    continue;
+                }
+            };
+
+            let mut line = String::new();
+            if BufReader::new(stream).read_line(&mut line).is_err() {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some("start") => {
+                    if running {
+                        warn!("profiler already running, ignore start");
+                        continue;
+                    }
+                    let path = parts.next().unwrap_or("./cita-bft.profiler").to_string();
+                    match PROFILER.lock().unwrap().start(path.clone()) {
+                        Ok(()) => {
+                            running = true;
+                            info!("profiler started, writing to {}", path);
+                        }
+                        Err(err) => warn!("profiler failed to start: {}", err),
+                    }
+                }
+                Some("stop") => {
+                    if !running {
+                        warn!("profiler not running, ignore stop");
+                        continue;
+                    }
+                    match PROFILER.lock().unwrap().stop() {
+                        Ok(()) => {
+                            running = false;
+                            info!("profiler stopped");
+                        }
+                        Err(err) => warn!("profiler failed to stop: {}", err),
+                    }
+                }
+                other => warn!("unknown profiler command: {:?}", other),
+            }
+        }
+    });
 }
 
 include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
@@ -97,10 +191,13 @@ fn main() {
         .args_from_usage("-c, --config=[FILE] 'Sets a custom config file'")
         .args_from_usage("-p, --private=[FILE] 'Sets a private key file'")
         .args_from_usage(
-            "--prof-start=[0] 'Specify the start time of profiling, zero means no profiling'",
+            "--prof-socket=[FILE] 'Unix socket for runtime profiling control (accepts `start [PATH]` / `stop`)'",
+        )
+        .args_from_usage(
+            "--max-forward-drift=[MS] 'Override the max forward clock-drift (ms) before a proposal is buffered'",
         )
         .args_from_usage(
-            "--prof-duration=[0] 'Specify the duration for profiling, zero means no profiling'",
+            "--protocol-version=[N] 'Override the wire-format version stamped on published consensus messages'",
         )
         .args_from_usage("-s, --stdout 'Log to console'")
         .get_matches();
@@ -121,16 +218,10 @@ fn main() {
         pk_path = p;
     }
 
-    let flag_prof_start = matches
-        .value_of("prof-start")
-        .unwrap_or("0")
-        .parse::<u64>()
-        .unwrap();
-    let flag_prof_duration = matches
-        .value_of("prof-duration")
-        .unwrap_or("0")
-        .parse::<u64>()
-        .unwrap();
+    let prof_socket = matches
+        .value_of("prof-socket")
+        .unwrap_or("./cita-bft.profiler.sock")
+        .to_string();
 
     // timer module
     let (main2timer, timer4main) = channel::unbounded();
@@ -159,22 +250,185 @@ fn main() {
         tx_sub,
         rx_pub,
     );
-    thread::spawn(move || loop {
-        let (key, body) = rx_sub.recv().unwrap();
-        let tx = sender.clone();
-        tx.send(BftTurn::Message((key, body))).unwrap();
-    });
+    // Last NTP offset (in milliseconds) measured by the NTP thread below. The
+    // dispatcher reads it to judge proposal freshness on the corrected timeline;
+    // it is also handed to the engine so proposals this node *produces* are
+    // stamped on the same timeline. Zero means "use the local clock unchanged".
+    let ntp_offset = Arc::new(AtomicI64::new(0));
+
+    // Consensus guard tunables (see `core::consensus_guard::GuardConfig`).
+    // They start from the defaults; the drift tolerance and the protocol
+    // version can be overridden on the command line at launch.
+    let mut guard_config = GuardConfig::default();
+    if let Some(ms) = matches.value_of("max-forward-drift") {
+        match ms.parse::<u64>() {
+            Ok(v) => guard_config.max_forward_time_drift = v,
+            Err(err) => warn!("ignore invalid --max-forward-drift {:?}: {}", ms, err),
+        }
+    }
+    if let Some(v) = matches.value_of("protocol-version") {
+        match v.parse::<u32>() {
+            Ok(v) => guard_config.protocol_version = v,
+            Err(err) => warn!("ignore invalid --protocol-version {:?}: {}", v, err),
+        }
+    }
+    info!("consensus guard: {:?}", guard_config);
+
+    // Far-future proposals are parked here keyed by `(height, round)`, and a
+    // single bounded timer thread re-delivers each one once local time reaches
+    // its stamp -- mirroring `WaitTimer`'s single-thread design instead of
+    // spawning one sleeping thread per proposal, so a leader flooding distinct
+    // height/round stamps cannot spawn unbounded threads.
+    let pending: Arc<Mutex<HashSet<(u64, u64)>>> = Arc::new(Mutex::new(HashSet::new()));
+    let (buf_tx, buf_rx) = channel::unbounded::<(u64, (u64, u64), (String, Vec<u8>))>();
+    {
+        let sender = sender.clone();
+        let pending = Arc::clone(&pending);
+        thread::spawn(move || {
+            // Min-heap on `(deliver_at, seq)`; the monotonic `seq` keeps the
+            // ordering total so the message payload is never compared.
+            let mut heap: BinaryHeap<Reverse<(u64, u64, (u64, u64), (String, Vec<u8>))>> =
+                BinaryHeap::new();
+            let mut seq: u64 = 0;
+            loop {
+                let next = match heap.peek() {
+                    Some(Reverse((at, ..))) => {
+                        let wait = at.saturating_sub(now_ms());
+                        if wait == 0 {
+                            None
+                        } else {
+                            buf_rx.recv_timeout(Duration::from_millis(wait)).ok()
+                        }
+                    }
+                    None => buf_rx.recv().ok(),
+                };
+                if let Some((at, slot, msg)) = next {
+                    heap.push(Reverse((at, seq, slot, msg)));
+                    seq += 1;
+                }
+                let now = now_ms();
+                while let Some(Reverse((at, ..))) = heap.peek() {
+                    if *at > now {
+                        break;
+                    }
+                    let Reverse((_, _, slot, msg)) = heap.pop().unwrap();
+                    pending.lock().unwrap().remove(&slot);
+                    sender.send(BftTurn::Message(msg)).unwrap();
+                }
+            }
+        });
+    }
+
+    // Proposals enter the engine through this queue as `BftTurn::Message`.
+    // Each message carries an explicit protocol-version field: any version in
+    // the guard's `min_protocol_version..=max_protocol_version` range is
+    // decodable during a rolling upgrade, and anything else is dropped with a
+    // counted warning so a
+    // validator set can migrate the wire format without a synchronized restart.
+    // A compact signed proposal's block timestamp is then classified by
+    // `GuardConfig` against the NTP-corrected clock: one slightly ahead is
+    // buffered for re-delivery, one beyond the buffer horizon or older than the
+    // drift tolerance is dropped, and everything else is forwarded untouched.
+    {
+        let ntp_offset = Arc::clone(&ntp_offset);
+        let guard = guard_config.clone();
+        thread::spawn(move || {
+            let mut unsupported_version_count: u64 = 0;
+            loop {
+                let (key, body) = rx_sub.recv().unwrap();
+
+                // Negotiate the wire-format version before doing anything else.
+                let version = match Message::try_from(&body) {
+                    Ok(msg) => msg.get_version(),
+                    Err(err) => {
+                        unsupported_version_count += 1;
+                        warn!(
+                            "drop undecodable consensus message ({} dropped so far): {:?}",
+                            unsupported_version_count, err
+                        );
+                        continue;
+                    }
+                };
+                if !guard.version_supported(version) {
+                    unsupported_version_count += 1;
+                    warn!(
+                        "drop consensus message with unsupported protocol version {} \
+                         (supported {}..={}), {} dropped so far",
+                        version,
+                        guard.min_protocol_version,
+                        guard.max_protocol_version,
+                        unsupported_version_count
+                    );
+                    continue;
+                }
+
+                if RoutingKey::from(&key) == routing_key!(Net >> CompactSignedProposal) {
+                    if let Some((height, round, proposal_ts)) = decode_proposal_meta(&body) {
+                        let now = guard.corrected_now_ms(ntp_offset.load(Ordering::Relaxed));
+                        match guard.classify(now, proposal_ts) {
+                            Ingress::Deliver => {}
+                            Ingress::Buffer { delay } => {
+                                let slot = (height, round);
+                                let mut buffered = pending.lock().unwrap();
+                                if buffered.contains(&slot) {
+                                    // Already parked for this height/round.
+                                } else if buffered.len() >= MAX_BUFFERED_PROPOSALS {
+                                    warn!(
+                                        "proposal buffer full, drop far-future proposal h={} r={}",
+                                        height, round
+                                    );
+                                } else {
+                                    buffered.insert(slot);
+                                    info!(
+                                        "buffer far-future proposal h={} r={}, re-deliver in {}ms",
+                                        height, round, delay
+                                    );
+                                    buf_tx.send((now_ms() + delay, slot, (key, body))).unwrap();
+                                }
+                                continue;
+                            }
+                            Ingress::DropBeyondHorizon => {
+                                warn!(
+                                    "drop far-future proposal h={} r={} beyond buffer horizon (ts={} now={})",
+                                    height, round, proposal_ts, now
+                                );
+                                continue;
+                            }
+                            Ingress::DropStale => {
+                                warn!(
+                                    "ignore stale proposal h={} r={} (ts={} now={})",
+                                    height, round, proposal_ts, now
+                                );
+                                continue;
+                            }
+                        }
+                    }
+                }
+
+                sender.send(BftTurn::Message((key, body))).unwrap();
+            }
+        });
+    }
 
     let config = Config::new(config_path);
 
     let pk = PrivateKey::new(pk_path);
 
     // main cita-bft loop module
+    //
+    // The engine is handed the shared NTP offset so the proposals it *produces*
+    // are stamped on the same corrected timeline the dispatcher uses to judge
+    // inbound freshness, plus the guard config so it stamps
+    // `protocol_version` onto every consensus message it publishes -- the
+    // egress counterpart to the ingress version gate above.
     let params = BftParams::new(&pk);
-    let mainthd = thread::spawn(move || {
-        let mut engine = Bft::new(tx_pub, main2timer, receiver, params);
-        engine.start();
-    });
+    let mainthd = {
+        let ntp_offset = Arc::clone(&ntp_offset);
+        thread::spawn(move || {
+            let mut engine = Bft::new(tx_pub, main2timer, receiver, params, ntp_offset, guard_config);
+            engine.start();
+        })
+    };
 
     // NTP service
     let ntp_config = config.ntp_config.clone();
@@ -186,24 +440,45 @@ fn main() {
     // };
     let mut log_tag: u8 = 0;
 
+    // Number of consecutive failed polls after which we stop trusting the
+    // last measured offset and fall back to the uncorrected local clock.
+    const NTP_FALLBACK_POLLS: u8 = 3;
+
     if ntp_config.enabled {
-        thread::spawn(move || loop {
-            if ntp_config.is_clock_offset_overflow() {
-                warn!("System clock seems off!!!");
-                log_tag += 1;
-                if log_tag == 10 {
-                    log_tag = 0;
-                    sleep(Duration::new(1000, 0));
+        let ntp_offset = Arc::clone(&ntp_offset);
+        thread::spawn(move || {
+            let mut fail_tag: u8 = 0;
+            loop {
+                match ntp_config.offset() {
+                    Ok(offset) => {
+                        fail_tag = 0;
+                        ntp_offset.store(offset.num_milliseconds(), Ordering::Relaxed);
+                        if offset.num_milliseconds().abs() > ntp_config.threshold {
+                            warn!("System clock seems off by {}!!!", offset);
+                            log_tag += 1;
+                            if log_tag == 10 {
+                                log_tag = 0;
+                                sleep(Duration::new(1000, 0));
+                            }
+                        } else {
+                            log_tag = 0;
+                        }
+                    }
+                    Err(err) => {
+                        fail_tag = fail_tag.saturating_add(1);
+                        if fail_tag >= NTP_FALLBACK_POLLS {
+                            warn!("Fetch ntp time failed {} times: {}, fall back to local clock", fail_tag, err);
+                            ntp_offset.store(0, Ordering::Relaxed);
+                        }
+                    }
                 }
-            } else {
-                log_tag = 0;
-            }
 
-            sleep(Duration::new(10, 0));
+                sleep(Duration::new(10, 0));
+            }
         });
     }
 
-    profiler(flag_prof_start, flag_prof_duration);
+    profiler(prof_socket);
 
     mainthd.join().unwrap();
     timethd.join().unwrap();